@@ -17,6 +17,62 @@ struct Args {
     /// One or more file or directory paths
     #[arg(required = true)]
     paths: Vec<String>,
+
+    /// 转码目标编码 (h265 或 av1)，BPP 超过阈值的文件会被转码
+    #[arg(long, value_enum)]
+    transcode: Option<TranscodeCodec>,
+
+    /// 触发转码的 BPP 阈值，默认 0.15 (15%)
+    #[arg(long, default_value_t = 0.15)]
+    threshold: f64,
+
+    /// ffmpeg CRF 值，数值越小画质越高、体积越大
+    #[arg(long, default_value_t = 23)]
+    crf: u32,
+
+    /// 转码成功后删除原文件（默认保留原文件，只生成新文件）
+    #[arg(long, default_value_t = false)]
+    replace: bool,
+
+    /// 目标 BPP，设置后按该值换算转码码率 (-b:v)，不再使用固定 CRF
+    #[arg(long)]
+    target_bpp: Option<f64>,
+
+    /// 将媒体信息 (分辨率/编码/帧率/码率/BPP) 作为标签写入文件名，不做转码
+    #[arg(long)]
+    rename_tags: bool,
+
+    /// 对 BPP 超过阈值的文件截取取样帧，拼接成预览图 <stem>_preview.jpg
+    #[arg(long)]
+    thumbs: bool,
+}
+
+// 取样帧在时长中的相对位置
+const THUMBNAIL_POSITIONS: &[f64] = &[0.1, 0.5, 0.9];
+
+// 码率下限，避免低分辨率/低帧率素材被换算出离谱的低码率
+const MIN_TRANSCODE_BITRATE: f64 = 200_000.0;
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+enum TranscodeCodec {
+    H265,
+    Av1,
+}
+
+impl TranscodeCodec {
+    fn encoder_name(&self) -> &'static str {
+        match self {
+            TranscodeCodec::H265 => "libx265",
+            TranscodeCodec::Av1 => "libsvtav1",
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            TranscodeCodec::H265 => "_H265",
+            TranscodeCodec::Av1 => "_AV1",
+        }
+    }
 }
 
 // 常见视频扩展名（小写）
@@ -25,24 +81,33 @@ const VIDEO_EXTENSIONS: &[&str] = &[
     "3gp", "ogv", "rmvb", "vob",
 ];
 
-// 已处理后缀
-// const SKIPPED_SUFFIXES: &[&str] = &["_H264", "_H265", "_AV1"];
-const SKIPPED_SUFFIXES: &[&str] = &["_AV1"];
+// 已处理后缀，取自 TranscodeCodec，避免和实际写出的文件名后缀脱节
+fn skipped_suffixes() -> [&'static str; 2] {
+    [TranscodeCodec::H265.suffix(), TranscodeCodec::Av1.suffix()]
+}
 
 #[derive(Deserialize, Debug)]
 struct Stream {
+    index: Option<u32>,
     codec_type: Option<String>,
     codec_name: Option<String>,
     width: Option<u32>,
     height: Option<u32>,
     r_frame_rate: Option<String>, // e.g., "25/1"
     bit_rate: Option<String>,
+    channels: Option<u32>,
     tags: Option<std::collections::HashMap<String, String>>,
 }
 
+#[derive(Deserialize, Debug)]
+struct Format {
+    duration: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct FfprobeOutput {
     streams: Vec<Stream>,
+    format: Option<Format>,
 }
 
 pub enum Color {
@@ -83,7 +148,7 @@ fn is_video_file(path: &Path) -> bool {
 fn should_skip_file(path: &Path) -> bool {
     if let Some(stem) = path.file_stem().and_then(OsStr::to_str) {
         let stem_upper = stem.to_uppercase();
-        for suffix in SKIPPED_SUFFIXES {
+        for suffix in skipped_suffixes() {
             if stem_upper.ends_with(suffix) {
                 return true;
             }
@@ -163,64 +228,148 @@ fn parse_frame_rate(r_frame_rate: &str) -> Option<f64> {
     None
 }
 
-fn calculate_bpp(probe: &FfprobeOutput) -> Option<f64> {
-    // 找到第一个视频流
-    let video_stream = probe.streams.iter().find(|s| {
+fn find_video_stream(probe: &FfprobeOutput) -> Option<&Stream> {
+    probe.streams.iter().find(|s| {
         s.codec_type.as_deref() == Some("video") && s.width.is_some() && s.height.is_some()
-    })?;
+    })
+}
 
-    let width = video_stream.width? as f64;
-    let height = video_stream.height? as f64;
-    let fps = parse_frame_rate(&video_stream.r_frame_rate.as_deref()?)?;
+// 按触发转码判断的那条视频流（calculate_bpp 取 max BPP 的同一条）换算目标码率，
+// 而不是无条件用第一条视频流——多视频轨文件里真正超阈值的未必是第一条。
+// 返回 (码率, 按该码率实际得到的 BPP)；码率被下限抬高时，后者会高于 target_bpp
+fn target_bitrate_for(probe: &FfprobeOutput, target_bpp: f64) -> Option<(f64, f64)> {
+    let (width, height, fps, ..) = probe
+        .streams
+        .iter()
+        .filter(|s| s.codec_type.as_deref() == Some("video"))
+        .filter_map(stream_bpp)
+        .max_by(|a, b| a.4.total_cmp(&b.4))?;
 
-    let bitrate: f64 = video_stream
+    let bitrate = (target_bpp * width * height * fps).max(MIN_TRANSCODE_BITRATE);
+    Some((bitrate, bitrate / (width * height * fps)))
+}
+
+// 计算单条视频流的 (width, height, fps, bitrate, bpp)
+fn stream_bpp(stream: &Stream) -> Option<(f64, f64, f64, f64, f64)> {
+    let width = stream.width? as f64;
+    let height = stream.height? as f64;
+    let fps = parse_frame_rate(stream.r_frame_rate.as_deref()?)?;
+
+    let bitrate: f64 = stream
         .bit_rate
         .as_ref()
         .and_then(|br| br.parse().ok())
         .or_else(|| {
-            video_stream
+            stream
                 .tags
                 .as_ref()
                 .and_then(|tags| tags.get("BPS"))
                 .and_then(|bps| bps.parse::<f64>().ok())
         })
-        .unwrap_or_else(|| return 0.0);
+        .unwrap_or(0.0);
 
-    let bpp_value = bitrate / (width * height * fps);
+    if width <= 0.0 || height <= 0.0 || fps <= 0.0 || bitrate <= 0.0 {
+        return None;
+    }
 
-    println!(
-        "BPS: {:.3}Mps {}x{} {:.2}fps ==> BPP: {}",
-        bitrate / 1000000.0,
-        width as i64,
-        height as i64,
-        fps,
-        color_text(
-            format!("{:.2}%", bpp_value * 100.0).as_str(),
-            if bpp_value < 0.1 {
-                Color::Green
-            } else if bpp_value < 0.15 {
-                Color::Yellow
-            } else {
-                Color::Red
+    let bpp = bitrate / (width * height * fps);
+    Some((width, height, fps, bitrate, bpp))
+}
+
+fn stream_audio_bitrate(stream: &Stream) -> Option<f64> {
+    stream
+        .bit_rate
+        .as_ref()
+        .and_then(|br| br.parse().ok())
+        .or_else(|| {
+            stream
+                .tags
+                .as_ref()
+                .and_then(|tags| tags.get("BPS"))
+                .and_then(|bps| bps.parse::<f64>().ok())
+        })
+}
+
+// 遍历所有视频/音频流，逐条打印信息，返回各视频轨中最高的 BPP（用于转码判断）
+fn calculate_bpp(probe: &FfprobeOutput) -> Option<f64> {
+    let mut max_bpp: Option<f64> = None;
+
+    for stream in probe.streams.iter().filter(|s| s.codec_type.as_deref() == Some("video")) {
+        let index = stream.index.unwrap_or_default();
+        let codec = stream.codec_name.as_deref().unwrap_or("unknown").to_uppercase();
+
+        match stream_bpp(stream) {
+            Some((width, height, fps, bitrate, bpp)) => {
+                println!(
+                    "Video #{} [{}] {:.3}Mps {}x{} {:.2}fps ==> BPP: {}",
+                    index,
+                    codec,
+                    bitrate / 1000000.0,
+                    width as i64,
+                    height as i64,
+                    fps,
+                    color_text(
+                        format!("{:.2}%", bpp * 100.0).as_str(),
+                        if bpp < 0.1 {
+                            Color::Green
+                        } else if bpp < 0.15 {
+                            Color::Yellow
+                        } else {
+                            Color::Red
+                        }
+                    )
+                );
+                max_bpp = Some(max_bpp.map_or(bpp, |m| m.max(bpp)));
             }
-        )
-    );
+            None => {
+                println!("Video #{} [{}] insufficient stream info, skipped", index, codec);
+            }
+        }
+    }
 
-    if width <= 0.0 || height <= 0.0 || fps <= 0.0 || bitrate <= 0.0 {
-        return None;
+    for stream in probe.streams.iter().filter(|s| s.codec_type.as_deref() == Some("audio")) {
+        let index = stream.index.unwrap_or_default();
+        let codec = stream.codec_name.as_deref().unwrap_or("unknown").to_uppercase();
+        let channels = stream
+            .channels
+            .map(|c| c.to_string())
+            .unwrap_or_else(|| "?".to_string());
+
+        match stream_audio_bitrate(stream) {
+            Some(bitrate) => println!(
+                "Audio #{} [{}] {}ch {:.0}Kbps",
+                index,
+                codec,
+                channels,
+                bitrate / 1000.0
+            ),
+            None => println!("Audio #{} [{}] {}ch", index, codec, channels),
+        }
     }
 
-    Some(bpp_value)
+    max_bpp
 }
 
+// 多视频轨时取分辨率最高的一条作为代表编码，而不是无条件用第一条视频流
 fn get_codec_name(probe: &FfprobeOutput) -> Option<String> {
     probe
         .streams
         .iter()
-        .find(|s| s.codec_type.as_deref() == Some("video"))
+        .filter(|s| s.codec_type.as_deref() == Some("video"))
+        .max_by_key(|s| s.width.unwrap_or(0) as u64 * s.height.unwrap_or(0) as u64)
         .and_then(|s| s.codec_name.clone())
 }
 
+// 所有视频轨的编码列表，用于判断是否整部文件都已经是 AV1
+fn video_codecs(probe: &FfprobeOutput) -> Vec<String> {
+    probe
+        .streams
+        .iter()
+        .filter(|s| s.codec_type.as_deref() == Some("video"))
+        .filter_map(|s| s.codec_name.clone())
+        .collect()
+}
+
 fn rename_with_suffix(original: &Path, suffix: &str) -> Result<()> {
     let parent = original.parent().unwrap_or_else(|| Path::new("."));
     let stem = original
@@ -247,6 +396,267 @@ fn rename_with_suffix(original: &Path, suffix: &str) -> Result<()> {
     Ok(())
 }
 
+fn format_tags(probe: &FfprobeOutput) -> Option<String> {
+    let codec = get_codec_name(probe)?.to_uppercase();
+    let (width, height, fps, bitrate, bpp) = stream_bpp(find_video_stream(probe)?)?;
+
+    Some(format!(
+        "[{}x{} {} {:.0}fps {:.1}Mbps BPP{:.0}%]",
+        width as i64,
+        height as i64,
+        codec,
+        fps,
+        bitrate / 1_000_000.0,
+        bpp * 100.0
+    ))
+}
+
+// 校验方括号内容是否确实是 format_tags 写出的格式: "WxH CODEC FPSfps BITRATEMbps BPPn%"
+// 而不是任意方括号内容（剪辑版本、发布组等），避免把它们当成标签块误删
+fn is_generated_tag_block(content: &str) -> bool {
+    let parts: Vec<&str> = content.split(' ').collect();
+    let [resolution, codec, fps, bitrate, bpp] = parts[..] else {
+        return false;
+    };
+
+    let resolution_ok = resolution
+        .split_once('x')
+        .is_some_and(|(w, h)| !w.is_empty() && !h.is_empty() && w.bytes().all(|b| b.is_ascii_digit()) && h.bytes().all(|b| b.is_ascii_digit()));
+    let codec_ok = !codec.is_empty() && codec.bytes().all(|b| b.is_ascii_alphanumeric());
+    let fps_ok = fps.strip_suffix("fps").is_some_and(|v| v.parse::<f64>().is_ok());
+    let bitrate_ok = bitrate.strip_suffix("Mbps").is_some_and(|v| v.parse::<f64>().is_ok());
+    let bpp_ok = bpp
+        .strip_prefix("BPP")
+        .and_then(|v| v.strip_suffix('%'))
+        .is_some_and(|v| v.parse::<f64>().is_ok());
+
+    resolution_ok && codec_ok && fps_ok && bitrate_ok && bpp_ok
+}
+
+// 去掉文件名末尾已有的标签块，使重复打标签是幂等的；只剥离我们自己写出的标签块，
+// 不动剪辑版本/发布组等其他方括号内容
+fn strip_tag_block(stem: &str) -> &str {
+    let trimmed = stem.trim_end();
+    if let Some(rest) = trimmed.strip_suffix(']') {
+        if let Some(open) = rest.rfind('[') {
+            let content = &rest[open + 1..];
+            if is_generated_tag_block(content) {
+                return trimmed[..open].trim_end();
+            }
+        }
+    }
+    stem
+}
+
+fn rename_with_tags(original: &Path, probe: &FfprobeOutput) -> Result<()> {
+    let tags = format_tags(probe)
+        .ok_or_else(|| anyhow!("Insufficient stream info to build tags for {:?}", original))?;
+
+    let parent = original.parent().unwrap_or_else(|| Path::new("."));
+    let stem = original
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| anyhow!("Invalid filename"))?;
+    let extension = original.extension().and_then(OsStr::to_str).unwrap_or("");
+
+    let base_stem = strip_tag_block(stem);
+    let new_name = if extension.is_empty() {
+        format!("{} {}", base_stem, tags)
+    } else {
+        format!("{} {}.{}", base_stem, tags, extension)
+    };
+
+    let new_path = parent.join(new_name);
+    if new_path == original {
+        println!("Tags already up to date: {}", original.display());
+        return Ok(());
+    }
+    if new_path.exists() {
+        return Err(anyhow!("Target file already exists: {:?}", new_path));
+    }
+
+    fs::rename(original, &new_path)
+        .with_context(|| format!("Failed to rename {:?} to {:?}", original, new_path))?;
+    println!("Tagged: {} -> {}", original.display(), new_path.display());
+    Ok(())
+}
+
+fn generate_thumbnails(file: &Path, probe: &FfprobeOutput) -> Result<()> {
+    let duration: f64 = probe
+        .format
+        .as_ref()
+        .and_then(|f| f.duration.as_ref())
+        .and_then(|d| d.parse().ok())
+        .ok_or_else(|| anyhow!("Missing duration for {:?}", file))?;
+
+    if duration <= 0.0 {
+        return Err(anyhow!("Invalid duration for {:?}", file));
+    }
+
+    let parent = file.parent().unwrap_or_else(|| Path::new("."));
+    let stem = file
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| anyhow!("Invalid filename"))?;
+    let preview_path = parent.join(format!("{}_preview.jpg", stem));
+    if preview_path.exists() {
+        return Err(anyhow!("Target file already exists: {:?}", preview_path));
+    }
+
+    let frame_paths: Vec<PathBuf> = (0..THUMBNAIL_POSITIONS.len())
+        .map(|i| parent.join(format!("{}_preview_frame{}.jpg", stem, i)))
+        .collect();
+    for frame_path in &frame_paths {
+        if frame_path.exists() {
+            return Err(anyhow!("Target file already exists: {:?}", frame_path));
+        }
+    }
+
+    let cleanup = |frame_paths: &[PathBuf]| {
+        for frame_path in frame_paths {
+            let _ = fs::remove_file(frame_path);
+        }
+    };
+
+    for (position, frame_path) in THUMBNAIL_POSITIONS.iter().zip(&frame_paths) {
+        let offset = duration * position;
+        let status = Command::new("ffmpeg")
+            .args(["-ss", &format!("{:.3}", offset)])
+            .arg("-i")
+            .arg(file)
+            .args(["-frames:v", "1"])
+            .arg(frame_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to run ffmpeg")?;
+
+        if !status.success() {
+            cleanup(&frame_paths);
+            return Err(anyhow!("Failed to extract sample frame from {:?}", file));
+        }
+    }
+
+    let inputs: String = (0..frame_paths.len()).map(|i| format!("[{}:v]", i)).collect();
+    let filter = format!(
+        "{}concat=n={}:v=1:a=0,tile={}x1[v]",
+        inputs,
+        frame_paths.len(),
+        frame_paths.len()
+    );
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-y");
+    for frame_path in &frame_paths {
+        cmd.arg("-i").arg(frame_path);
+    }
+    let status = cmd
+        .args(["-filter_complex", &filter])
+        .args(["-map", "[v]"])
+        .args(["-frames:v", "1"])
+        .arg(&preview_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("Failed to run ffmpeg")?;
+
+    cleanup(&frame_paths);
+
+    if !status.success() {
+        return Err(anyhow!("Failed to build contact sheet for {:?}", file));
+    }
+
+    println!("Thumbnails: {} -> {}", file.display(), preview_path.display());
+    Ok(())
+}
+
+fn transcode_file(
+    file: &Path,
+    codec: TranscodeCodec,
+    crf: u32,
+    target_bitrate: Option<f64>,
+    replace: bool,
+) -> Result<()> {
+    let parent = file.parent().unwrap_or_else(|| Path::new("."));
+    let stem = file
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| anyhow!("Invalid filename"))?;
+    let extension = file.extension().and_then(OsStr::to_str).unwrap_or("mkv");
+    let suffix = codec.suffix();
+
+    let tmp_path = parent.join(format!("{}{}.tmp.{}", stem, suffix, extension));
+    let final_path = parent.join(format!("{}{}.{}", stem, suffix, extension));
+    if final_path.exists() {
+        return Err(anyhow!("Target file already exists: {:?}", final_path));
+    }
+
+    println!("Transcoding: {} -> {}", file.display(), final_path.display());
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-y")
+        .arg("-i")
+        .arg(file)
+        .args(["-map", "0"])
+        .args(["-c:v", codec.encoder_name()]);
+
+    match target_bitrate {
+        Some(bitrate) => {
+            cmd.args(["-b:v", &format!("{}", bitrate as u64)])
+                .args(["-maxrate", &format!("{}", (bitrate * 1.5) as u64)])
+                .args(["-bufsize", &format!("{}", (bitrate * 2.0) as u64)]);
+        }
+        None => {
+            cmd.args(["-crf", &crf.to_string()]);
+        }
+    }
+
+    let status = cmd
+        .args(["-c:a", "copy", "-c:s", "copy"])
+        .arg(&tmp_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("Failed to run ffmpeg")?;
+
+    if !status.success() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(anyhow!("ffmpeg transcode failed for {:?}", file));
+    }
+
+    let probe = run_ffprobe(&tmp_path).context("Failed to probe transcoded output")?;
+    if get_codec_name(&probe).is_none() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(anyhow!("Transcoded output does not decode: {:?}", tmp_path));
+    }
+
+    let original_size = fs::metadata(file)?.len();
+    let new_size = fs::metadata(&tmp_path)?.len();
+    if new_size >= original_size {
+        println!(
+            "{}",
+            color_text(
+                &format!(
+                    "Warning: transcoded file is not smaller ({} -> {} bytes)",
+                    original_size, new_size
+                ),
+                Color::Yellow
+            )
+        );
+    }
+
+    fs::rename(&tmp_path, &final_path)
+        .with_context(|| format!("Failed to rename {:?} to {:?}", tmp_path, final_path))?;
+    println!("Transcoded: {} -> {}", file.display(), final_path.display());
+
+    if replace {
+        fs::remove_file(file).with_context(|| format!("Failed to remove original {:?}", file))?;
+        println!("Removed original: {}", file.display());
+    }
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().skip(1).collect();
     if args.is_empty() {
@@ -285,17 +695,72 @@ fn main() -> Result<()> {
             }
         };
 
-        let codec = get_codec_name(&probe)
-            .unwrap_or_else(|| "unknown".to_string())
-            .to_uppercase();
+        if args.rename_tags {
+            if let Err(e) = rename_with_tags(&file, &probe) {
+                eprintln!("Failed to tag {}: {}", file.display(), e);
+            }
+            continue;
+        }
+
+        let codecs = video_codecs(&probe);
+        let all_av1 = !codecs.is_empty() && codecs.iter().all(|c| c.to_uppercase() == "AV1");
 
-        if codec.to_uppercase() == "AV1" {
+        if all_av1 {
             rename_with_suffix(&file, "_AV1")?; // 重命名为 _AV1
             continue;
         }
 
         // 计算 BPP
-        let _ = calculate_bpp(&probe);
+        let bpp = calculate_bpp(&probe);
+
+        if args.thumbs && bpp.is_some_and(|bpp| bpp > args.threshold) {
+            if let Err(e) = generate_thumbnails(&file, &probe) {
+                eprintln!("Failed to generate thumbnails for {}: {}", file.display(), e);
+            }
+        }
+
+        if let Some(codec) = args.transcode {
+            if let Some(bpp) = bpp {
+                if bpp > args.threshold {
+                    let mut skip = false;
+                    let target_bitrate = match args.target_bpp {
+                        Some(target_bpp) if bpp <= target_bpp => {
+                            println!(
+                                "Skipping transcode: current BPP {:.2}% already at/below target {:.2}%",
+                                bpp * 100.0,
+                                target_bpp * 100.0
+                            );
+                            skip = true;
+                            None
+                        }
+                        Some(target_bpp) => {
+                            let target = target_bitrate_for(&probe, target_bpp);
+                            match target {
+                                Some((_, projected_bpp)) => println!(
+                                    "Projected BPP: {:.2}% -> {:.2}%",
+                                    bpp * 100.0,
+                                    projected_bpp * 100.0
+                                ),
+                                None => eprintln!(
+                                    "Missing width/height/fps for {}, falling back to CRF mode",
+                                    file.display()
+                                ),
+                            }
+                            target.map(|(bitrate, _)| bitrate)
+                        }
+                        None => None,
+                    };
+
+                    if !skip {
+                        if let Err(e) =
+                            transcode_file(&file, codec, args.crf, target_bitrate, args.replace)
+                        {
+                            eprintln!("Failed to transcode {}: {}", file.display(), e);
+                        }
+                    }
+                }
+            }
+        }
     }
 
     // 程序结束，随便播放一个提示声